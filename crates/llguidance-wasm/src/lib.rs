@@ -4,20 +4,25 @@
 //! constrained generation library, enabling grammar-based token validation
 //! for use with transformer.js.
 
-use js_sys::Uint8Array;
+use js_sys::{Uint32Array, Uint8Array};
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::sync::Arc;
 use wasm_bindgen::prelude::*;
 
-use llguidance::api::TopLevelGrammar;
+use llguidance::api::{GrammarWithLexer, TopLevelGrammar};
 use llguidance::toktrie::{ApproximateTokEnv, TokRxInfo, TokTrie};
 use llguidance::{Matcher, ParserFactory};
 
-/// Grammar definition passed from JavaScript
+/// Grammar definition passed from JavaScript. Every entry in `grammars` is
+/// compiled, not just the first (see `LLGuidanceParser::convert_grammar`).
+/// `root` selects which entry parsing starts from, defaulting to the last
+/// one since an entry can only reference sub-grammars that come before it.
 #[derive(Debug, Deserialize)]
 struct GrammarInput {
     grammars: Vec<GrammarSpec>,
+    #[serde(default)]
+    root: Option<usize>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -42,11 +47,14 @@ struct TokenizerInput {
     /// Added tokens (special tokens)
     #[serde(default)]
     added_tokens: Vec<AddedToken>,
-    /// Model type (e.g., "bpe", "wordpiece")
-    /// For Transformer.js compatibility, we keep this field but it is not used.
+    /// Model type (e.g., "bpe", "unigram", "bpe-sp", "wordpiece")
+    /// Drives which byte-decoding rules are applied to `vocab` entries.
     #[serde(default)]
-    #[allow(dead_code)]
     model_type: Option<String>,
+    /// WordPiece continuation prefix (default `"##"`), used to tell a
+    /// word-continuation subword from a word-initial one.
+    #[serde(default = "default_continuing_subword_prefix")]
+    continuing_subword_prefix: String,
     /// Special token IDs
     #[serde(default)]
     eos_token_id: Option<u32>,
@@ -108,6 +116,10 @@ where
     deserializer.deserialize_seq(MergesVisitor)
 }
 
+fn default_continuing_subword_prefix() -> String {
+    "##".to_string()
+}
+
 #[derive(Debug, Deserialize)]
 struct AddedToken {
     id: u32,
@@ -116,12 +128,131 @@ struct AddedToken {
     special: bool,
 }
 
+/// What a compilation diagnostic is about, mirroring the distinct failure
+/// modes of the grammar/tokenizer pipeline: a malformed Lark source, an
+/// invalid JSON Schema, a regex that doesn't compile, or a tokenizer vocab
+/// llguidance can't build a trie from.
+#[derive(Debug, Clone, Copy)]
+enum DiagnosticKind {
+    LarkSyntax,
+    JsonSchema,
+    RegexCompile,
+    TokenizerVocab,
+}
+
+impl DiagnosticKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            DiagnosticKind::LarkSyntax => "LarkSyntax",
+            DiagnosticKind::JsonSchema => "JsonSchema",
+            DiagnosticKind::RegexCompile => "RegexCompile",
+            DiagnosticKind::TokenizerVocab => "TokenizerVocab",
+        }
+    }
+}
+
+/// A single structured diagnostic describing why a grammar or tokenizer
+/// failed to compile, with enough position info for an interactive grammar
+/// editor to underline the offending span.
+#[derive(Debug, Clone)]
+struct GrammarDiagnostic {
+    message: String,
+    line: Option<u32>,
+    column: Option<u32>,
+    kind: DiagnosticKind,
+}
+
+impl GrammarDiagnostic {
+    fn new(kind: DiagnosticKind, message: impl Into<String>) -> Self {
+        GrammarDiagnostic {
+            message: message.into(),
+            line: None,
+            column: None,
+            kind,
+        }
+    }
+
+    fn at(kind: DiagnosticKind, message: impl Into<String>, line: u32, column: u32) -> Self {
+        GrammarDiagnostic {
+            message: message.into(),
+            line: Some(line),
+            column: Some(column),
+            kind,
+        }
+    }
+
+    fn from_json_error(kind: DiagnosticKind, err: &serde_json::Error) -> Self {
+        GrammarDiagnostic::at(
+            kind,
+            err.to_string(),
+            err.line() as u32,
+            err.column() as u32,
+        )
+    }
+}
+
+/// One or more diagnostics collected while compiling a grammar or
+/// tokenizer. Converts to a JS array of `{ message, line, column, kind }`
+/// objects, so a caller building an interactive grammar editor can report
+/// every error at once instead of bailing on the first.
+#[derive(Debug, Clone)]
+struct GrammarDiagnostics(Vec<GrammarDiagnostic>);
+
+impl From<GrammarDiagnostic> for GrammarDiagnostics {
+    fn from(diagnostic: GrammarDiagnostic) -> Self {
+        GrammarDiagnostics(vec![diagnostic])
+    }
+}
+
+impl From<GrammarDiagnostics> for JsValue {
+    fn from(diagnostics: GrammarDiagnostics) -> JsValue {
+        let array = js_sys::Array::new();
+        for diagnostic in &diagnostics.0 {
+            let obj = js_sys::Object::new();
+            let _ = js_sys::Reflect::set(
+                &obj,
+                &JsValue::from_str("message"),
+                &JsValue::from_str(&diagnostic.message),
+            );
+            let _ = js_sys::Reflect::set(
+                &obj,
+                &JsValue::from_str("line"),
+                &diagnostic.line.map(JsValue::from).unwrap_or(JsValue::NULL),
+            );
+            let _ = js_sys::Reflect::set(
+                &obj,
+                &JsValue::from_str("column"),
+                &diagnostic
+                    .column
+                    .map(JsValue::from)
+                    .unwrap_or(JsValue::NULL),
+            );
+            let _ = js_sys::Reflect::set(
+                &obj,
+                &JsValue::from_str("kind"),
+                &JsValue::from_str(diagnostic.kind.as_str()),
+            );
+            array.push(&obj);
+        }
+        array.into()
+    }
+}
+
 /// The main parser struct exposed to JavaScript
 #[wasm_bindgen]
 pub struct LLGuidanceParser {
     factory: Arc<ParserFactory>,
     matcher: Matcher,
     vocab_size: usize,
+    /// The grammar the matcher was last built from, kept around so
+    /// `rollback` can rebuild a fresh matcher to replay into.
+    grammar: TopLevelGrammar,
+    /// Every token consumed since the matcher was (re)built, in order.
+    /// `checkpoint`/`rollback` use this as the resumable prefix: a
+    /// checkpoint is just a length into this history, and rolling back
+    /// means rebuilding the matcher and re-feeding the prefix up to that
+    /// length.
+    token_history: Vec<u32>,
 }
 
 #[wasm_bindgen]
@@ -133,10 +264,13 @@ impl LLGuidanceParser {
         #[cfg(feature = "console_error_panic_hook")]
         console_error_panic_hook::set_once();
 
-        Self::new_inner(grammar_json, tokenizer_json).map_err(|e| JsValue::from_str(&e))
+        Self::new_inner(grammar_json, tokenizer_json).map_err(JsValue::from)
     }
 
-    fn new_inner(grammar_json: &str, tokenizer_json: &str) -> Result<LLGuidanceParser, String> {
+    fn new_inner(
+        grammar_json: &str,
+        tokenizer_json: &str,
+    ) -> Result<LLGuidanceParser, GrammarDiagnostics> {
         // Parse the grammar
         let grammar = Self::parse_grammar(grammar_json)?;
 
@@ -145,8 +279,12 @@ impl LLGuidanceParser {
         let vocab_size = tok_env.tok_trie().vocab_size();
 
         // Create parser factory
-        let mut factory = ParserFactory::new_simple(&tok_env)
-            .map_err(|e| format!("Failed to create parser factory: {}", e))?;
+        let mut factory = ParserFactory::new_simple(&tok_env).map_err(|e| {
+            GrammarDiagnostic::new(
+                DiagnosticKind::TokenizerVocab,
+                format!("Failed to create parser factory: {}", e),
+            )
+        })?;
 
         // Minimal logging
         factory.set_stderr_log_level(0);
@@ -154,27 +292,33 @@ impl LLGuidanceParser {
         let factory = Arc::new(factory);
 
         // Create the parser and matcher
-        let parser = factory.create_parser(grammar);
+        let parser = factory.create_parser(grammar.clone());
         let matcher = Matcher::new(parser);
 
         Ok(LLGuidanceParser {
             factory,
             matcher,
             vocab_size,
+            grammar,
+            token_history: Vec::new(),
         })
     }
 
     /// Create a tokenizer environment from the JSON configuration
     fn create_tok_env(
         tokenizer_json: &str,
-    ) -> Result<Arc<dyn llguidance::toktrie::TokenizerEnv + Sync>, String> {
+    ) -> Result<Arc<dyn llguidance::toktrie::TokenizerEnv + Sync>, GrammarDiagnostics> {
         // Try to parse as TokenizerInput
         let input: TokenizerInput = serde_json::from_str(tokenizer_json)
-            .map_err(|e| format!("Failed to parse tokenizer JSON: {}", e))?;
+            .map_err(|e| GrammarDiagnostic::from_json_error(DiagnosticKind::TokenizerVocab, &e))?;
 
         // Check if we have a valid vocabulary
         if input.vocab.is_empty() {
-            return Err("Tokenizer vocabulary is empty".to_string());
+            return Err(GrammarDiagnostic::new(
+                DiagnosticKind::TokenizerVocab,
+                "Tokenizer vocabulary is empty",
+            )
+            .into());
         }
 
         // Find the maximum token ID to determine vocab size
@@ -185,20 +329,26 @@ impl LLGuidanceParser {
         // Each entry is the byte representation of the token
         let mut words: Vec<Vec<u8>> = vec![Vec::new(); vocab_size];
 
+        let model_type = input
+            .model_type
+            .as_deref()
+            .unwrap_or("bpe")
+            .to_ascii_lowercase();
+
         for (token_str, id) in &input.vocab {
             if (*id as usize) < vocab_size {
                 // Handle special token encoding
                 // llguidance uses \xFF prefix for special tokens
-                let bytes = if input.added_tokens.iter().any(|t| t.id == *id && t.special) {
+                let is_special = input.added_tokens.iter().any(|t| t.id == *id && t.special)
+                    || (model_type == "wordpiece" && is_wordpiece_special_token(token_str));
+                let bytes = if is_special {
                     // Special tokens get the \xFF prefix
                     let mut special_bytes = vec![0xFF];
                     special_bytes.extend(token_str.as_bytes());
                     special_bytes
                 } else {
-                    // Regular tokens: decode the token string
-                    // GPT-2 style tokenizers use 'Ġ' (U+0120) to represent space
-                    // and other Unicode characters for byte encoding
-                    decode_token_bytes(token_str)
+                    // Regular tokens: decode according to the tokenizer's model type
+                    decode_token_bytes(token_str, &model_type, &input.continuing_subword_prefix)
                 };
                 words[*id as usize] = bytes;
             }
@@ -236,7 +386,7 @@ impl LLGuidanceParser {
         Ok(Arc::new(tok_env))
     }
 
-    fn parse_grammar(grammar_json: &str) -> Result<TopLevelGrammar, String> {
+    fn parse_grammar(grammar_json: &str) -> Result<TopLevelGrammar, GrammarDiagnostics> {
         // Try to parse as our simplified GrammarInput format first (most common case)
         if let Ok(input) = serde_json::from_str::<GrammarInput>(grammar_json) {
             if !input.grammars.is_empty() {
@@ -246,29 +396,205 @@ impl LLGuidanceParser {
 
         // Fall back to parsing directly as TopLevelGrammar (native .ll.json format)
         serde_json::from_str::<TopLevelGrammar>(grammar_json)
-            .map_err(|e| format!("Failed to parse grammar JSON: {}", e))
+            .map_err(|e| GrammarDiagnostic::from_json_error(DiagnosticKind::JsonSchema, &e).into())
     }
 
-    fn convert_grammar(input: &GrammarInput) -> Result<TopLevelGrammar, String> {
+    /// Build a `TopLevelGrammar` out of every entry in `input.grammars`,
+    /// naming each one (`g0`, `g1`, ...) so a Lark entry can reference an
+    /// earlier one via `@sub(i)`/`@json`. The entry at `input.root` is
+    /// moved to position 0, since that's where `TopLevelGrammar` starts
+    /// parsing from.
+    fn convert_grammar(input: &GrammarInput) -> Result<TopLevelGrammar, GrammarDiagnostics> {
         if input.grammars.is_empty() {
-            return Err("No grammars provided".to_string());
+            return Err(
+                GrammarDiagnostic::new(DiagnosticKind::JsonSchema, "No grammars provided").into(),
+            );
+        }
+
+        let root_index = input.root.unwrap_or(input.grammars.len() - 1);
+        if root_index >= input.grammars.len() {
+            return Err(GrammarDiagnostic::new(
+                DiagnosticKind::JsonSchema,
+                format!(
+                    "root index {root_index} is out of bounds for {} grammars",
+                    input.grammars.len()
+                ),
+            )
+            .into());
+        }
+
+        let names: Vec<String> = (0..input.grammars.len()).map(|i| format!("g{i}")).collect();
+
+        // Build every entry even if an earlier one fails, so a caller
+        // fixing up a grammar in an editor sees all the problems at once
+        // instead of one at a time.
+        let mut grammars = Vec::with_capacity(input.grammars.len());
+        let mut errors = Vec::new();
+        for (i, spec) in input.grammars.iter().enumerate() {
+            match Self::build_sub_grammar(spec, i, &names, &input.grammars) {
+                Ok(sub) => grammars.push(sub),
+                Err(diags) => errors.extend(diags.0),
+            }
         }
+        if !errors.is_empty() {
+            return Err(GrammarDiagnostics(errors));
+        }
+
+        let root = grammars.remove(root_index);
+        grammars.insert(0, root);
+
+        Ok(TopLevelGrammar {
+            grammars,
+            ..Default::default()
+        })
+    }
 
-        // For now, handle the first grammar only
-        let spec = &input.grammars[0];
+    /// Compile a single entry of `input.grammars` into a named
+    /// `GrammarWithLexer`, resolving any `@sub(i)`/`@json` references along
+    /// the way.
+    fn build_sub_grammar(
+        spec: &GrammarSpec,
+        index: usize,
+        names: &[String],
+        all: &[GrammarSpec],
+    ) -> Result<GrammarWithLexer, GrammarDiagnostics> {
+        let kind = match spec {
+            GrammarSpec::JsonSchema { .. } => DiagnosticKind::JsonSchema,
+            GrammarSpec::Regex { .. } => DiagnosticKind::RegexCompile,
+            GrammarSpec::Lark { .. } => DiagnosticKind::LarkSyntax,
+        };
 
-        match spec {
+        let single = match spec {
             GrammarSpec::JsonSchema { json_schema } => {
-                // Use TopLevelGrammar::from_json_schema
-                Ok(TopLevelGrammar::from_json_schema(json_schema.clone()))
+                TopLevelGrammar::from_json_schema(json_schema.clone())
             }
             GrammarSpec::Regex { rx } => {
                 // Create a lark grammar that matches the regex
-                let lark_grammar = format!("start: /{}/", rx);
-                Ok(TopLevelGrammar::from_lark(lark_grammar))
+                TopLevelGrammar::from_lark(format!("start: /{}/", rx))
+            }
+            GrammarSpec::Lark { lark } => {
+                let resolved = Self::resolve_sub_grammar_refs(lark, index, names, all)?;
+                TopLevelGrammar::from_lark(resolved)
+            }
+        };
+
+        let mut sub = single.grammars.into_iter().next().ok_or_else(|| {
+            GrammarDiagnostic::new(
+                kind,
+                format!("Grammar {index} did not produce a compiled sub-grammar"),
+            )
+        })?;
+        sub.name = Some(names[index].clone());
+        Ok(sub)
+    }
+
+    /// Rewrite `@sub(i)` and `@json` references in a Lark sub-grammar's
+    /// source into `@name{}`, llguidance's own syntax for referencing
+    /// another named grammar within the same `TopLevelGrammar`. Keeps
+    /// scanning past a malformed reference instead of stopping at the
+    /// first one, so every problem in the source is reported together,
+    /// each with the 1-based line/column where it starts.
+    fn resolve_sub_grammar_refs(
+        lark: &str,
+        index: usize,
+        names: &[String],
+        all: &[GrammarSpec],
+    ) -> Result<String, GrammarDiagnostics> {
+        let mut result = String::with_capacity(lark.len());
+        let mut errors = Vec::new();
+        let mut i = 0;
+        let mut line = 1u32;
+        let mut column = 1u32;
+        while i < lark.len() {
+            if let Some(rest) = lark[i..].strip_prefix("@sub(") {
+                match rest.find(')') {
+                    Some(end) => {
+                        let digits = rest[..end].trim();
+                        match digits.parse::<usize>() {
+                            Ok(target) if target < index => {
+                                result.push('@');
+                                result.push_str(&names[target]);
+                                result.push_str("{}");
+                            }
+                            Ok(target) => errors.push(GrammarDiagnostic::at(
+                                DiagnosticKind::LarkSyntax,
+                                format!(
+                                    "Grammar {index}: @sub({target}) must reference an earlier grammar"
+                                ),
+                                line,
+                                column,
+                            )),
+                            Err(_) => errors.push(GrammarDiagnostic::at(
+                                DiagnosticKind::LarkSyntax,
+                                format!("Grammar {index}: invalid @sub({digits}) reference"),
+                                line,
+                                column,
+                            )),
+                        }
+                        let consumed = "@sub(".len() + end + 1;
+                        advance_position(&lark[i..i + consumed], &mut line, &mut column);
+                        i += consumed;
+                    }
+                    None => {
+                        errors.push(GrammarDiagnostic::at(
+                            DiagnosticKind::LarkSyntax,
+                            format!("Grammar {index}: unterminated @sub(...) reference"),
+                            line,
+                            column,
+                        ));
+                        advance_position(&lark[i..i + "@sub(".len()], &mut line, &mut column);
+                        i += "@sub(".len();
+                    }
+                }
+            } else if lark[i..].starts_with("@json")
+                && !Self::continues_identifier(&lark[i + "@json".len()..])
+            {
+                match all[..index]
+                    .iter()
+                    .rposition(|g| matches!(g, GrammarSpec::JsonSchema { .. }))
+                {
+                    Some(target) => {
+                        result.push('@');
+                        result.push_str(&names[target]);
+                        result.push_str("{}");
+                    }
+                    None => errors.push(GrammarDiagnostic::at(
+                        DiagnosticKind::LarkSyntax,
+                        format!(
+                            "Grammar {index}: @json has no preceding JSON-schema grammar to reference"
+                        ),
+                        line,
+                        column,
+                    )),
+                }
+                advance_position(&lark[i..i + "@json".len()], &mut line, &mut column);
+                i += "@json".len();
+            } else {
+                let ch = lark[i..].chars().next().expect("i < lark.len()");
+                result.push(ch);
+                if ch == '\n' {
+                    line += 1;
+                    column = 1;
+                } else {
+                    column += 1;
+                }
+                i += ch.len_utf8();
             }
-            GrammarSpec::Lark { lark } => Ok(TopLevelGrammar::from_lark(lark.clone())),
         }
+
+        if errors.is_empty() {
+            Ok(result)
+        } else {
+            Err(GrammarDiagnostics(errors))
+        }
+    }
+
+    /// True if `rest` starts with a character that could continue an
+    /// identifier begun just before it (e.g. the `path` in `@jsonpath`, or
+    /// the `(` in a hypothetical `@json(...)`). Used to make sure `@json`
+    /// only matches as a whole word, not as a prefix of some other `@name`.
+    fn continues_identifier(rest: &str) -> bool {
+        matches!(rest.chars().next(), Some(c) if c.is_alphanumeric() || c == '_' || c == '(')
     }
 
     /// Check if a specific token is allowed at the current position
@@ -285,10 +611,20 @@ impl LLGuidanceParser {
     /// Get the full token mask for the current position
     #[wasm_bindgen]
     pub fn get_token_mask(&mut self) -> Result<Uint8Array, JsValue> {
+        let mask_vec = self.mask_bytes().map_err(|e| JsValue::from_str(&e))?;
+
+        let js_array = Uint8Array::new_with_length(mask_vec.len() as u32);
+        js_array.copy_from(&mask_vec);
+        Ok(js_array)
+    }
+
+    /// One byte per token (`1` if allowed, `0` otherwise), built by testing
+    /// every token individually against the computed mask.
+    fn mask_bytes(&mut self) -> Result<Vec<u8>, String> {
         let mask = self
             .matcher
             .compute_mask()
-            .map_err(|e| JsValue::from_str(&format!("Failed to compute mask: {}", e)))?;
+            .map_err(|e| format!("Failed to compute mask: {}", e))?;
 
         let mut mask_vec = vec![0u8; self.vocab_size];
         for (i, item) in mask_vec.iter_mut().enumerate().take(self.vocab_size) {
@@ -296,18 +632,117 @@ impl LLGuidanceParser {
                 *item = 1;
             }
         }
+        Ok(mask_vec)
+    }
 
-        let js_array = Uint8Array::new_with_length(mask_vec.len() as u32);
-        js_array.copy_from(&mask_vec);
+    /// Get the full token mask for the current position as a packed
+    /// bitmask: word `i / 32` of the returned array holds bit `i % 32` set
+    /// when token `i` is allowed, least-significant bit first. Cheaper than
+    /// `get_token_mask` for large vocabularies since it copies the mask's
+    /// own packed words instead of testing each token individually.
+    #[wasm_bindgen]
+    pub fn get_token_mask_bits(&mut self) -> Result<Uint32Array, JsValue> {
+        let words = self.mask_words().map_err(|e| JsValue::from_str(&e))?;
+
+        let js_array = Uint32Array::new_with_length(words.len() as u32);
+        js_array.copy_from(&words);
         Ok(js_array)
     }
 
+    /// The mask's own packed `u32` words, copied directly rather than
+    /// rebuilt one token at a time.
+    fn mask_words(&mut self) -> Result<Vec<u32>, String> {
+        let mask = self
+            .matcher
+            .compute_mask()
+            .map_err(|e| format!("Failed to compute mask: {}", e))?;
+
+        let words = mask.as_slice().to_vec();
+        debug_assert_eq!(words.len(), packed_word_count(self.vocab_size));
+        Ok(words)
+    }
+
     /// Advance the parser state after a token has been selected
     #[wasm_bindgen]
     pub fn advance(&mut self, token_id: u32) -> Result<(), JsValue> {
         self.matcher
             .consume_token(token_id)
             .map_err(|e| JsValue::from_str(&format!("Failed to consume token: {}", e)))?;
+        self.token_history.push(token_id);
+        Ok(())
+    }
+
+    /// Get the tokens the grammar forces next, if any. When the grammar
+    /// allows only one possible continuation (e.g. a fixed JSON key or
+    /// punctuation), the caller can skip `get_token_mask`/`advance` for each
+    /// of these tokens and jump straight to `consume_tokens`.
+    #[wasm_bindgen]
+    pub fn compute_ff_tokens(&mut self) -> Result<Uint32Array, JsValue> {
+        let tokens = self
+            .compute_ff_tokens_inner()
+            .map_err(|e| JsValue::from_str(&e))?;
+
+        let js_array = Uint32Array::new_with_length(tokens.len() as u32);
+        js_array.copy_from(&tokens);
+        Ok(js_array)
+    }
+
+    fn compute_ff_tokens_inner(&mut self) -> Result<Vec<u32>, String> {
+        self.matcher
+            .compute_ff_tokens()
+            .map_err(|e| format!("Failed to compute forced tokens: {}", e))
+    }
+
+    /// Validate and apply several tokens in one call, as returned by
+    /// `compute_ff_tokens`. This is equivalent to calling `advance` once per
+    /// token, but avoids recomputing a mask for spans the grammar has
+    /// already determined are deterministic.
+    #[wasm_bindgen]
+    pub fn consume_tokens(&mut self, token_ids: Vec<u32>) -> Result<(), JsValue> {
+        self.consume_tokens_inner(&token_ids)
+            .map_err(|e| JsValue::from_str(&e))
+    }
+
+    fn consume_tokens_inner(&mut self, token_ids: &[u32]) -> Result<(), String> {
+        self.matcher
+            .consume_tokens(token_ids)
+            .map_err(|e| format!("Failed to consume tokens: {}", e))?;
+        self.token_history.extend_from_slice(token_ids);
+        Ok(())
+    }
+
+    /// Snapshot the current matcher state and return an opaque handle that
+    /// can later be passed to `rollback`. Cheap: it's just a length into the
+    /// token history, not a clone of the matcher itself.
+    #[wasm_bindgen]
+    pub fn checkpoint(&self) -> u32 {
+        self.token_history.len() as u32
+    }
+
+    /// Restore the matcher to the state it was in when `handle` was
+    /// returned by `checkpoint`, discarding any tokens consumed since.
+    /// Implemented by rebuilding a fresh matcher from the original grammar
+    /// and re-feeding the accepted token prefix, so the resulting mask and
+    /// `stop_reason` match exactly what they were at checkpoint time.
+    #[wasm_bindgen]
+    pub fn rollback(&mut self, handle: u32) -> Result<(), JsValue> {
+        self.rollback_inner(handle as usize)
+            .map_err(|e| JsValue::from_str(&e))
+    }
+
+    fn rollback_inner(&mut self, handle: usize) -> Result<(), String> {
+        if handle > self.token_history.len() {
+            return Err("Invalid checkpoint handle: past the end of the token history".to_string());
+        }
+
+        let parser = self.factory.create_parser(self.grammar.clone());
+        let mut matcher = Matcher::new(parser);
+        matcher
+            .consume_tokens(&self.token_history[..handle])
+            .map_err(|e| format!("Failed to replay tokens: {}", e))?;
+
+        self.token_history.truncate(handle);
+        self.matcher = matcher;
         Ok(())
     }
 
@@ -324,9 +759,11 @@ impl LLGuidanceParser {
     /// Reset the parser to its initial state
     #[wasm_bindgen]
     pub fn reset(&mut self, grammar_json: &str) -> Result<(), JsValue> {
-        let grammar = Self::parse_grammar(grammar_json).map_err(|e| JsValue::from_str(&e))?;
-        let parser = self.factory.create_parser(grammar);
+        let grammar = Self::parse_grammar(grammar_json).map_err(JsValue::from)?;
+        let parser = self.factory.create_parser(grammar.clone());
         self.matcher = Matcher::new(parser);
+        self.grammar = grammar;
+        self.token_history.clear();
         Ok(())
     }
 
@@ -343,9 +780,21 @@ impl LLGuidanceParser {
     }
 }
 
+/// Decode a token string to its byte representation, dispatching on the
+/// tokenizer's `model_type`.
+fn decode_token_bytes(token: &str, model_type: &str, continuing_subword_prefix: &str) -> Vec<u8> {
+    match model_type {
+        // SentencePiece-backed tokenizers (Llama, T5) report either the BPE
+        // or Unigram algorithm, but both use the same byte/space encoding.
+        "unigram" | "bpe-sp" => decode_sentencepiece_token_bytes(token),
+        "wordpiece" => decode_wordpiece_token_bytes(token, continuing_subword_prefix),
+        _ => decode_bpe_token_bytes(token),
+    }
+}
+
 /// Decode a token string to its byte representation
 /// Handles GPT-2/BPE style encoding where special Unicode characters represent bytes
-fn decode_token_bytes(token: &str) -> Vec<u8> {
+fn decode_bpe_token_bytes(token: &str) -> Vec<u8> {
     let mut result = Vec::new();
 
     for c in token.chars() {
@@ -377,9 +826,355 @@ fn decode_token_bytes(token: &str) -> Vec<u8> {
     result
 }
 
+/// Decode a SentencePiece token (Llama/T5-style Unigram or BPE vocab) to its
+/// byte representation. SentencePiece marks a leading space with '▁'
+/// (U+2581) and only ever emits a real byte via a `<0xAB>` byte-fallback
+/// token, never as a literal ASCII space.
+fn decode_sentencepiece_token_bytes(token: &str) -> Vec<u8> {
+    if let Some(byte) = decode_byte_fallback_token(token) {
+        return vec![byte];
+    }
+
+    let mut result = Vec::new();
+    for c in token.chars() {
+        match c {
+            '\u{2581}' => result.push(b' '),
+            c => {
+                let mut buf = [0u8; 4];
+                result.extend(c.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+    }
+    result
+}
+
+/// Decode a WordPiece token (BERT-style vocab). A `##`-prefixed token is a
+/// word-continuation and contributes its suffix bytes with no leading space;
+/// any other token is word-initial and gets a leading space.
+fn decode_wordpiece_token_bytes(token: &str, continuing_subword_prefix: &str) -> Vec<u8> {
+    if !continuing_subword_prefix.is_empty() {
+        if let Some(suffix) = token.strip_prefix(continuing_subword_prefix) {
+            return suffix.as_bytes().to_vec();
+        }
+    }
+
+    let mut result = Vec::with_capacity(token.len() + 1);
+    result.push(b' ');
+    result.extend(token.as_bytes());
+    result
+}
+
+/// Recognize a byte-fallback token of the exact shape `<0xAB>` (two hex
+/// digits), as emitted by SentencePiece vocabularies, and return the raw
+/// byte it represents. Anything else (including a grammar literal like
+/// `<0x41>` embedded in regular text) is left alone by returning `None`.
+fn decode_byte_fallback_token(token: &str) -> Option<u8> {
+    let bytes = token.as_bytes();
+    if bytes.len() == 6
+        && token.starts_with("<0x")
+        && token.ends_with('>')
+        && bytes[3..5].iter().all(u8::is_ascii_hexdigit)
+    {
+        u8::from_str_radix(&token[3..5], 16).ok()
+    } else {
+        None
+    }
+}
+
+/// The small set of WordPiece special-token names (BERT-family vocabs) that
+/// should be treated as special tokens even when not listed in
+/// `added_tokens` with `special: true`.
+fn is_wordpiece_special_token(token: &str) -> bool {
+    matches!(token, "[UNK]" | "[CLS]" | "[SEP]" | "[PAD]" | "[MASK]")
+}
+
+/// Advance `line`/`column` past every character in `consumed`, the same way
+/// the main scan loop in `resolve_sub_grammar_refs` tracks position one
+/// character at a time.
+fn advance_position(consumed: &str, line: &mut u32, column: &mut u32) {
+    for ch in consumed.chars() {
+        if ch == '\n' {
+            *line += 1;
+            *column = 1;
+        } else {
+            *column += 1;
+        }
+    }
+}
+
+/// Number of `u32` words needed to pack one allowed/disallowed bit per
+/// token, rounding up for vocab sizes that aren't a multiple of 32.
+fn packed_word_count(vocab_size: usize) -> usize {
+    (vocab_size + 31) / 32
+}
+
 /// Initialize the WASM module
 #[wasm_bindgen(start)]
 pub fn init() {
     #[cfg(feature = "console_error_panic_hook")]
     console_error_panic_hook::set_once();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a real `LLGuidanceParser` over a tiny fixed vocabulary (`a`,
+    /// `b`, and an EOS token) for tests that need to drive the matcher
+    /// end-to-end rather than just exercise a pure helper function.
+    fn test_parser(grammar_json: &str) -> LLGuidanceParser {
+        let tokenizer_json = serde_json::json!({
+            "vocab": {"a": 0, "b": 1, "</s>": 2},
+            "added_tokens": [{"id": 2, "content": "</s>", "special": true}],
+            "eos_token_id": 2
+        })
+        .to_string();
+
+        LLGuidanceParser::new_inner(grammar_json, &tokenizer_json)
+            .expect("fixture grammar and tokenizer must be valid")
+    }
+
+    #[test]
+    fn compute_ff_tokens_forces_the_only_possible_continuation() {
+        let mut parser = test_parser(r#"{"grammars":[{"lark":"start: \"a\" \"b\""}]}"#);
+
+        let forced = parser
+            .compute_ff_tokens_inner()
+            .expect("mask is computable for a fresh parser");
+        assert!(
+            !forced.is_empty(),
+            "a grammar with only one possible string must force its tokens"
+        );
+
+        parser
+            .consume_tokens_inner(&forced)
+            .expect("forced tokens must be accepted by the same grammar that forced them");
+        assert!(parser.is_complete());
+    }
+
+    #[test]
+    fn rollback_restores_the_mask_and_stop_reason_from_a_checkpoint() {
+        let mut parser = test_parser(r#"{"grammars":[{"lark":"start: \"a\" \"b\""}]}"#);
+
+        let checkpoint = parser.checkpoint();
+        let mask_before = parser.mask_bytes().expect("mask is computable");
+        let stop_reason_before = parser.stop_reason();
+
+        parser
+            .consume_tokens_inner(&[0])
+            .expect("token 'a' is valid at the start of the grammar");
+        assert_ne!(
+            parser.mask_bytes().expect("mask is computable"),
+            mask_before,
+            "consuming a token must change what's allowed next"
+        );
+
+        parser
+            .rollback_inner(checkpoint as usize)
+            .expect("checkpoint was taken on this same parser");
+
+        assert_eq!(parser.checkpoint(), checkpoint);
+        assert_eq!(
+            parser.mask_bytes().expect("mask is computable"),
+            mask_before
+        );
+        assert_eq!(parser.stop_reason(), stop_reason_before);
+    }
+
+    #[test]
+    fn mask_words_agrees_bit_for_bit_with_mask_bytes() {
+        // mask_bytes (get_token_mask) tests every token individually via
+        // is_allowed; mask_words (get_token_mask_bits) instead copies the
+        // mask's own packed word storage on the assumption that its layout
+        // matches. Cross-check the two independently computed masks to
+        // catch a wrong assumption about that layout.
+        let mut parser = test_parser(r#"{"grammars":[{"lark":"start: \"a\" \"b\""}]}"#);
+
+        let bytes = parser.mask_bytes().expect("mask is computable");
+        let words = parser.mask_words().expect("mask is computable");
+
+        for (i, &byte) in bytes.iter().enumerate() {
+            let bit_set = (words[i / 32] >> (i % 32)) & 1 == 1;
+            assert_eq!(
+                bit_set,
+                byte == 1,
+                "token {i} disagrees between get_token_mask and get_token_mask_bits"
+            );
+        }
+    }
+
+    #[test]
+    fn resolve_sub_grammar_refs_rewrites_sub_and_json() {
+        let specs = vec![
+            GrammarSpec::JsonSchema {
+                json_schema: serde_json::json!({"type": "object"}),
+            },
+            GrammarSpec::Regex {
+                rx: "[A-Z]+".to_string(),
+            },
+        ];
+        let names = vec!["g0".to_string(), "g1".to_string()];
+
+        let resolved = LLGuidanceParser::resolve_sub_grammar_refs(
+            "start: @json \" \" @sub(1)",
+            2,
+            &names,
+            &specs,
+        )
+        .expect("both references are valid");
+
+        assert_eq!(resolved, "start: @g0{} \" \" @g1{}");
+    }
+
+    #[test]
+    fn resolve_sub_grammar_refs_leaves_lookalike_identifiers_alone() {
+        // `@jsonpath` merely starts with "@json"; it must pass through
+        // untouched rather than being corrupted into `@g0{}path`.
+        let specs = vec![GrammarSpec::JsonSchema {
+            json_schema: serde_json::json!({"type": "object"}),
+        }];
+        let names = vec!["g0".to_string()];
+
+        let resolved =
+            LLGuidanceParser::resolve_sub_grammar_refs("start: @jsonpath", 1, &names, &specs)
+                .expect("not a @json reference, so nothing to resolve");
+
+        assert_eq!(resolved, "start: @jsonpath");
+    }
+
+    #[test]
+    fn resolve_sub_grammar_refs_rejects_forward_reference() {
+        let specs = vec![GrammarSpec::Lark {
+            lark: "start: @sub(1)".to_string(),
+        }];
+        let names = vec!["g0".to_string()];
+
+        let err =
+            LLGuidanceParser::resolve_sub_grammar_refs("@sub(1)", 0, &names, &specs).unwrap_err();
+
+        assert_eq!(err.0.len(), 1);
+        assert!(err.0[0]
+            .message
+            .contains("must reference an earlier grammar"));
+    }
+
+    #[test]
+    fn resolve_sub_grammar_refs_reports_line_and_column_of_the_error() {
+        let specs = vec![GrammarSpec::Lark {
+            lark: "start: \"a\"\n@sub(9)".to_string(),
+        }];
+        let names = vec!["g0".to_string()];
+
+        let err =
+            LLGuidanceParser::resolve_sub_grammar_refs("start: \"a\"\n@sub(9)", 0, &names, &specs)
+                .unwrap_err();
+
+        assert_eq!(err.0.len(), 1);
+        assert_eq!(err.0[0].line, Some(2));
+        assert_eq!(err.0[0].column, Some(1));
+    }
+
+    #[test]
+    fn resolve_sub_grammar_refs_collects_every_error() {
+        // Neither reference is valid at index 0: there's no preceding
+        // JSON-schema grammar, and @sub(5) is out of range. Both problems
+        // must be reported, not just the first one encountered.
+        let specs = vec![GrammarSpec::Lark {
+            lark: "start: @json @sub(5)".to_string(),
+        }];
+        let names = vec!["g0".to_string()];
+
+        let err =
+            LLGuidanceParser::resolve_sub_grammar_refs("start: @json @sub(5)", 0, &names, &specs)
+                .unwrap_err();
+
+        assert_eq!(err.0.len(), 2);
+    }
+
+    #[test]
+    fn convert_grammar_moves_root_to_front() {
+        // Entry 2 (the Lark composer) references entries 0 and 1, so it
+        // can't come first in the input array; convert_grammar should
+        // still make it the parse root by moving it to position 0.
+        let input = GrammarInput {
+            grammars: vec![
+                GrammarSpec::JsonSchema {
+                    json_schema: serde_json::json!({"type": "string"}),
+                },
+                GrammarSpec::Regex {
+                    rx: "</done>".to_string(),
+                },
+                GrammarSpec::Lark {
+                    lark: "start: @sub(0) @sub(1)".to_string(),
+                },
+            ],
+            root: None,
+        };
+
+        let grammar = LLGuidanceParser::convert_grammar(&input).expect("valid composition");
+
+        assert_eq!(grammar.grammars[0].name.as_deref(), Some("g2"));
+    }
+
+    #[test]
+    fn convert_grammar_rejects_out_of_bounds_root() {
+        let input = GrammarInput {
+            grammars: vec![GrammarSpec::Regex {
+                rx: "a".to_string(),
+            }],
+            root: Some(5),
+        };
+
+        let err = LLGuidanceParser::convert_grammar(&input).unwrap_err();
+        assert_eq!(err.0.len(), 1);
+        assert!(err.0[0].message.contains("out of bounds"));
+    }
+
+    #[test]
+    fn packed_word_count_rounds_up_to_a_whole_word() {
+        assert_eq!(packed_word_count(0), 0);
+        assert_eq!(packed_word_count(1), 1);
+        assert_eq!(packed_word_count(32), 1);
+        assert_eq!(packed_word_count(33), 2);
+        assert_eq!(packed_word_count(128_000), 4000);
+        assert_eq!(packed_word_count(128_001), 4001);
+    }
+
+    #[test]
+    fn decode_byte_fallback_token_matches_exact_shape() {
+        assert_eq!(decode_byte_fallback_token("<0x41>"), Some(0x41));
+        assert_eq!(decode_byte_fallback_token("<0x00>"), Some(0x00));
+        assert_eq!(decode_byte_fallback_token("<0xff>"), Some(0xFF));
+        // Too short/long to be the exact `<0xAB>` shape.
+        assert_eq!(decode_byte_fallback_token("<0x4>"), None);
+        assert_eq!(decode_byte_fallback_token("<0x411>"), None);
+        // Not hex digits.
+        assert_eq!(decode_byte_fallback_token("<0xZZ>"), None);
+        // A grammar literal that merely looks like a byte-fallback token
+        // must not be collapsed.
+        assert_eq!(decode_byte_fallback_token("hello <0x41> world"), None);
+    }
+
+    #[test]
+    fn decode_sentencepiece_token_bytes_handles_leading_space_and_byte_fallback() {
+        assert_eq!(decode_sentencepiece_token_bytes("\u{2581}hello"), b" hello");
+        assert_eq!(decode_sentencepiece_token_bytes("world"), b"world");
+        assert_eq!(decode_sentencepiece_token_bytes("<0x0A>"), vec![0x0A]);
+    }
+
+    #[test]
+    fn decode_wordpiece_token_bytes_splits_continuation_from_word_initial() {
+        assert_eq!(decode_wordpiece_token_bytes("##ing", "##"), b"ing");
+        assert_eq!(decode_wordpiece_token_bytes("hello", "##"), b" hello");
+        // An empty continuation prefix can never match, so every token is
+        // treated as word-initial.
+        assert_eq!(decode_wordpiece_token_bytes("##ing", ""), b" ##ing");
+    }
+
+    #[test]
+    fn decode_token_bytes_dispatches_on_model_type() {
+        assert_eq!(decode_token_bytes("\u{2581}hi", "unigram", "##"), b" hi");
+        assert_eq!(decode_token_bytes("##ing", "wordpiece", "##"), b"ing");
+        assert_eq!(decode_token_bytes("\u{0120}hi", "bpe", "##"), b" hi");
+    }
+}